@@ -1,23 +1,202 @@
+#[cfg(target_os = "macos")]
+use fuser::ReplyXTimes;
 use fuser::{
     fuse_forget_one, Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData,
     ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek,
     ReplyOpen, ReplyPoll, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use libc::{c_int, ENOSYS, EPERM};
-use opentelemetry::trace::{FutureExt, Span, Tracer, TracerProvider};
+use libc::c_int;
+use opentelemetry::logs::{LogRecord, Logger, LoggerProvider, Severity};
+use opentelemetry::metrics::{Counter, Histogram, Meter, MeterProvider, Unit};
+use opentelemetry::trace::{Link, Status, TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::{Context, ContextGuard, KeyValue};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::path::Path;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Attribute keys recorded on the spans of `OpentelemetryFuser`
+pub mod attribute {
+    use libc::c_int;
+    use opentelemetry::KeyValue;
+    use std::borrow::Cow;
+    use std::ffi::OsStr;
+
+    /// Unique id of the FUSE request
+    pub const REQUEST_UNIQUE: &str = "fuse.request.unique";
+    /// User id of the calling process
+    pub const REQUEST_UID: &str = "fuse.request.uid";
+    /// Group id of the calling process
+    pub const REQUEST_GID: &str = "fuse.request.gid";
+    /// Process id of the calling process
+    pub const REQUEST_PID: &str = "fuse.request.pid";
+    /// Command name of the calling process, from `/proc/<pid>/comm`
+    pub const REQUEST_COMM: &str = "fuse.request.comm";
+    /// Executable path of the calling process, from `/proc/<pid>/exe`
+    pub const REQUEST_EXE: &str = "fuse.request.exe";
+    /// Control group of the calling process, from `/proc/<pid>/cgroup`
+    pub const REQUEST_CGROUP: &str = "fuse.request.cgroup";
+    /// Inode the operation applies to
+    pub const INO: &str = "fuse.ino";
+    /// Inode of the parent directory
+    pub const PARENT: &str = "fuse.parent";
+    /// Entry name within the parent directory
+    pub const NAME: &str = "fuse.name";
+    /// Inode of the new parent directory (`rename`, `link`)
+    pub const NEW_PARENT: &str = "fuse.new_parent";
+    /// Entry name within the new parent directory (`rename`, `link`)
+    pub const NEW_NAME: &str = "fuse.new_name";
+    /// Byte offset in a file, or offset in a directory stream
+    pub const OFFSET: &str = "fuse.offset";
+    /// Size in bytes
+    pub const SIZE: &str = "fuse.size";
+    /// File handle
+    pub const FH: &str = "fuse.fh";
+    /// Operation flags
+    pub const FLAGS: &str = "fuse.flags";
+    /// Symbolic name of the error code returned by the operation (e.g. `EIO`)
+    pub const ERRNO: &str = "fuse.errno";
+    /// Name of the `Filesystem` method, on metrics (e.g. `lookup`)
+    pub const OPERATION: &str = "fuse.operation";
+    /// Outcome of the operation (`ok` or `error`), on metrics, when the wrapper can observe it
+    pub const OUTCOME: &str = "fuse.outcome";
+    /// Duration of the operation in seconds, on log records
+    pub const DURATION: &str = "fuse.duration";
+
+    // Inodes, handles and unique ids are opaque `u64` values: they are recorded
+    // bit-for-bit as `i64` since OpenTelemetry has no unsigned integer type.
+
+    pub(super) fn ino(ino: u64) -> KeyValue {
+        KeyValue::new(INO, ino as i64)
+    }
+
+    pub(super) fn parent(parent: u64) -> KeyValue {
+        KeyValue::new(PARENT, parent as i64)
+    }
+
+    pub(super) fn name(name: &OsStr) -> KeyValue {
+        KeyValue::new(NAME, name.to_string_lossy().into_owned())
+    }
+
+    pub(super) fn new_parent(new_parent: u64) -> KeyValue {
+        KeyValue::new(NEW_PARENT, new_parent as i64)
+    }
+
+    pub(super) fn new_name(new_name: &OsStr) -> KeyValue {
+        KeyValue::new(NEW_NAME, new_name.to_string_lossy().into_owned())
+    }
+
+    pub(super) fn offset(offset: i64) -> KeyValue {
+        KeyValue::new(OFFSET, offset)
+    }
+
+    pub(super) fn size(size: u64) -> KeyValue {
+        KeyValue::new(SIZE, size as i64)
+    }
+
+    pub(super) fn fh(fh: u64) -> KeyValue {
+        KeyValue::new(FH, fh as i64)
+    }
+
+    pub(super) fn flags(flags: i64) -> KeyValue {
+        KeyValue::new(FLAGS, flags)
+    }
+
+    pub(super) fn errno(errno: c_int) -> KeyValue {
+        KeyValue::new(ERRNO, errno_label(errno))
+    }
+
+    /// Symbolic name of an error code, or its number if it has no known name
+    pub(super) fn errno_label(errno: c_int) -> Cow<'static, str> {
+        match errno_name(errno) {
+            Some(name) => Cow::Borrowed(name),
+            None => Cow::Owned(errno.to_string()),
+        }
+    }
+
+    /// Symbolic name of an error code, for the codes a filesystem commonly returns
+    pub(super) fn errno_name(errno: c_int) -> Option<&'static str> {
+        let name = match errno {
+            libc::EPERM => "EPERM",
+            libc::ENOENT => "ENOENT",
+            libc::EINTR => "EINTR",
+            libc::EIO => "EIO",
+            libc::ENXIO => "ENXIO",
+            libc::E2BIG => "E2BIG",
+            libc::EBADF => "EBADF",
+            libc::EAGAIN => "EAGAIN",
+            libc::ENOMEM => "ENOMEM",
+            libc::EACCES => "EACCES",
+            libc::EFAULT => "EFAULT",
+            libc::EBUSY => "EBUSY",
+            libc::EEXIST => "EEXIST",
+            libc::EXDEV => "EXDEV",
+            libc::ENODEV => "ENODEV",
+            libc::ENOTDIR => "ENOTDIR",
+            libc::EISDIR => "EISDIR",
+            libc::EINVAL => "EINVAL",
+            libc::ENFILE => "ENFILE",
+            libc::EMFILE => "EMFILE",
+            libc::ENOTTY => "ENOTTY",
+            libc::EFBIG => "EFBIG",
+            libc::ENOSPC => "ENOSPC",
+            libc::ESPIPE => "ESPIPE",
+            libc::EROFS => "EROFS",
+            libc::EMLINK => "EMLINK",
+            libc::EPIPE => "EPIPE",
+            libc::ERANGE => "ERANGE",
+            libc::EDEADLK => "EDEADLK",
+            libc::ENAMETOOLONG => "ENAMETOOLONG",
+            libc::ENOLCK => "ENOLCK",
+            libc::ENOSYS => "ENOSYS",
+            libc::ENOTEMPTY => "ENOTEMPTY",
+            libc::ELOOP => "ELOOP",
+            libc::ENODATA => "ENODATA",
+            libc::EOPNOTSUPP => "EOPNOTSUPP",
+            libc::ETIMEDOUT => "ETIMEDOUT",
+            libc::ESTALE => "ESTALE",
+            libc::ECANCELED => "ECANCELED",
+            _ => return None,
+        };
+        Some(name)
+    }
+}
 
 /// Wrapper around a `fuser::Filesystem` implementation to trace calls
 pub struct OpentelemetryFuser<TyFs, TyTracer> {
     fs: TyFs,
     tracer: TyTracer,
+    span_filter: SpanFilter,
+    metrics: Option<Metrics>,
+    /// `fuse.session` span, from `init` to `destroy`
+    session: Option<Context>,
+    /// Whether to group the requests on a file handle under a `fuse.file_handle` span
+    file_handle_spans: bool,
+    /// Open `fuse.file_handle` spans, by inode and file handle
+    handles: HashMap<(u64, u64), Context>,
+    /// Details of the calling processes, when enabled
+    processes: Option<ProcessCache>,
+    /// Emitter of log records about failed and slow operations
+    logs: Option<LogEmitter>,
+    /// Operations taking at least this long are logged as slow
+    slow_operation_threshold: Option<Duration>,
 }
 
 impl<TyFs, TyTracer> OpentelemetryFuser<TyFs, TyTracer> {
     pub fn new(fs: TyFs, tracer: TyTracer) -> Self {
-        Self { fs, tracer }
+        Self {
+            fs,
+            tracer,
+            span_filter: SpanFilter::default(),
+            metrics: None,
+            session: None,
+            file_handle_spans: false,
+            handles: HashMap::new(),
+            processes: None,
+            logs: None,
+            slow_operation_threshold: None,
+        }
     }
 
     pub fn new_with_provider<TyTracerProvider>(fs: TyFs, provider: &TyTracerProvider) -> Self
@@ -26,53 +205,673 @@ impl<TyFs, TyTracer> OpentelemetryFuser<TyFs, TyTracer> {
     {
         Self::new(fs, provider.tracer("fuser"))
     }
+
+    /// Also record operation counts, durations and I/O sizes with the given meter
+    pub fn with_meter(mut self, meter: &Meter) -> Self {
+        self.metrics = Some(Metrics::new(meter));
+        self
+    }
+
+    /// Also record operation counts, durations and I/O sizes with a meter from the given provider
+    pub fn with_meter_provider<TyMeterProvider>(self, provider: &TyMeterProvider) -> Self
+    where
+        TyMeterProvider: MeterProvider,
+    {
+        self.with_meter(&provider.meter("fuser"))
+    }
+
+    /// Enable or disable the spans of an operation, named after its `Filesystem` method
+    /// (e.g. `getattr`)
+    ///
+    /// Panics if `operation` is not the name of a `Filesystem` method.
+    pub fn with_operation_traced(self, operation: &str, traced: bool) -> Self {
+        self.with_operation_sampling_ratio(operation, if traced { 1.0 } else { 0.0 })
+    }
+
+    /// Only record the spans of a `ratio` (between 0 and 1) of the calls to an operation, named
+    /// after its `Filesystem` method (e.g. `read`)
+    ///
+    /// The decision is derived from the request unique id, independently of the SDK sampler.
+    ///
+    /// Panics if `operation` is not the name of a `Filesystem` method.
+    pub fn with_operation_sampling_ratio(mut self, operation: &str, ratio: f64) -> Self {
+        assert!(
+            OPERATIONS.contains(&operation),
+            "unknown FUSE operation `{operation}`"
+        );
+        self.span_filter
+            .sampling_ratios
+            .insert(operation.to_string(), ratio);
+        self
+    }
+
+    /// Drop the spans of operations that complete successfully in less than `min_duration`
+    ///
+    /// With a non-zero minimum, spans are only created once the operation has returned, so the
+    /// operation span is not the current span while the inner filesystem runs: spans created by
+    /// the inner filesystem are not nested under it.
+    pub fn with_min_span_duration(mut self, min_duration: Duration) -> Self {
+        self.span_filter.min_duration = min_duration;
+        self
+    }
+
+    /// Parent the spans of requests on an open file or directory handle under a
+    /// `fuse.file_handle` span, ending when the handle is released
+    ///
+    /// The handle chosen by `open` is not visible to the wrapper, so the handle span starts
+    /// with the first request that uses it.
+    pub fn with_file_handle_spans(mut self, enabled: bool) -> Self {
+        self.file_handle_spans = enabled;
+        self
+    }
+
+    /// Record the command name, executable path and control group of the calling process on
+    /// spans, read from `/proc/<pid>` and cached per pid for `ttl`
+    ///
+    /// Details the filesystem process is not allowed to read are omitted.
+    pub fn with_process_info(mut self, ttl: Duration) -> Self {
+        self.processes = Some(ProcessCache::new(ttl));
+        self
+    }
+
+    /// Emit log records about failed operations and, with a slow operation threshold, slow
+    /// operations through the given logger
+    ///
+    /// Records are emitted with the operation span as the current span, so that they carry its
+    /// trace context, and are not built when the logger disables their severity.
+    pub fn with_logger<TyLogger>(mut self, logger: TyLogger) -> Self
+    where
+        TyLogger: Logger + Send + 'static,
+    {
+        self.logs = Some(LogEmitter::new(logger));
+        self
+    }
+
+    /// Emit log records through a logger from the given provider (see `with_logger`)
+    pub fn with_logger_provider<TyLoggerProvider>(self, provider: &TyLoggerProvider) -> Self
+    where
+        TyLoggerProvider: LoggerProvider,
+        TyLoggerProvider::Logger: Send + 'static,
+    {
+        self.with_logger(provider.logger("fuser"))
+    }
+
+    /// Log operations taking at least `threshold` as slow, when a logger is configured
+    pub fn with_slow_operation_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_operation_threshold = Some(threshold);
+        self
+    }
+}
+
+/// Type-erased OpenTelemetry `Logger`
+struct LogEmitter(Box<dyn Fn(Severity, String, Vec<KeyValue>) + Send>);
+
+impl LogEmitter {
+    fn new<TyLogger>(logger: TyLogger) -> Self
+    where
+        TyLogger: Logger + Send + 'static,
+    {
+        Self(Box::new(move |severity, body, attributes| {
+            if !logger.event_enabled(severity, "fuser") {
+                return;
+            }
+            let mut record = logger.create_log_record();
+            record.set_timestamp(SystemTime::now());
+            record.set_severity_number(severity);
+            record.set_severity_text(severity.name().into());
+            record.set_body(body.into());
+            record.add_attributes(attributes.into_iter().map(|kv| (kv.key, kv.value)));
+            logger.emit(record);
+        }))
+    }
+
+    fn emit(&self, severity: Severity, body: String, attributes: Vec<KeyValue>) {
+        (self.0)(severity, body, attributes)
+    }
+}
+
+/// Selection of the spans recorded by `OpentelemetryFuser`
+#[derive(Default)]
+struct SpanFilter {
+    /// Sampling ratio per operation, operations without a ratio are always recorded
+    sampling_ratios: HashMap<String, f64>,
+    /// Spans of operations completing faster than this are dropped
+    min_duration: Duration,
+}
+
+impl SpanFilter {
+    /// Whether to record the span of `operation` for the request `unique`, if it is slow enough
+    fn samples(&self, operation: &str, unique: u64) -> bool {
+        match self.sampling_ratios.get(operation) {
+            Some(&ratio) => sample(unique, ratio),
+            None => true,
+        }
+    }
+}
+
+/// Sampling decision for a request: the unique id is hashed (with the splitmix64 finalizer) so
+/// that consecutive ids are spread uniformly, then compared to the ratio.
+fn sample(unique: u64, ratio: f64) -> bool {
+    if ratio >= 1.0 {
+        return true;
+    }
+    if ratio <= 0.0 || ratio.is_nan() {
+        return false;
+    }
+    let mut x = unique.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    ((x >> 11) as f64 / (1u64 << 53) as f64) < ratio
+}
+
+/// Instruments recorded by `OpentelemetryFuser` when a meter is configured
+struct Metrics {
+    /// `fuse.operations`: number of operations
+    operations: Counter<u64>,
+    /// `fuse.operation.duration`: time spent in the inner filesystem, in seconds
+    duration: Histogram<f64>,
+    /// `fuse.read.requested_bytes`: bytes requested by `read` calls
+    read_bytes: Counter<u64>,
+    /// `fuse.write.bytes`: bytes passed to `write` calls
+    write_bytes: Counter<u64>,
 }
 
-impl<TyFs, TyTracer> Filesystem for OpentelemetryFuser<TyFs, TyTracer>
+impl Metrics {
+    fn new(meter: &Meter) -> Self {
+        Self {
+            operations: meter
+                .u64_counter("fuse.operations")
+                .with_description("Number of FUSE operations")
+                .init(),
+            duration: meter
+                .f64_histogram("fuse.operation.duration")
+                .with_description("Time spent handling FUSE operations")
+                .with_unit(Unit::new("s"))
+                .init(),
+            read_bytes: meter
+                .u64_counter("fuse.read.requested_bytes")
+                .with_description("Bytes requested by FUSE read operations")
+                .with_unit(Unit::new("By"))
+                .init(),
+            write_bytes: meter
+                .u64_counter("fuse.write.bytes")
+                .with_description("Bytes passed to FUSE write operations")
+                .with_unit(Unit::new("By"))
+                .init(),
+        }
+    }
+
+    fn record(&self, operation: &'static str, outcome: Option<&'static str>, duration: Duration) {
+        let mut attributes = vec![KeyValue::new(attribute::OPERATION, operation)];
+        attributes.extend(outcome.map(|outcome| KeyValue::new(attribute::OUTCOME, outcome)));
+        self.operations.add(1, &attributes);
+        self.duration.record(duration.as_secs_f64(), &attributes);
+    }
+}
+
+impl<TyFs, TyTracer> OpentelemetryFuser<TyFs, TyTracer>
 where
-    TyFs: Filesystem,
     TyTracer: Tracer,
+    TyTracer::Span: Send + Sync + 'static,
 {
-    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
-        let mut span = self.tracer.start("Filesystem::init");
-        let res = self.fs.init(req, config);
-        span.end();
-        res
+    /// Start the span of an operation, if it is sampled
+    ///
+    /// Without a minimum span duration, the span is started right away so that it can be the
+    /// current span while the inner filesystem runs. Otherwise, it is only built by
+    /// `end_operation` once the duration is known, and the inner filesystem runs in the
+    /// parent context.
+    ///
+    /// The span is linked to the session span, and is a child of the span of `handle` if any,
+    /// which is started here if the request uses it so that unsampled requests start no handle
+    /// span.
+    fn start_operation(
+        &mut self,
+        operation: &'static str,
+        name: &'static str,
+        req: Option<&Request<'_>>,
+        handle: Option<RequestHandle>,
+        start: SystemTime,
+        attributes: impl FnOnce() -> Vec<KeyValue>,
+    ) -> Option<OperationSpan> {
+        let unique = req.map_or(0, Request::unique);
+        if !self.span_filter.samples(operation, unique) {
+            return None;
+        }
+        if let Some(RequestHandle::Uses(handle)) = handle {
+            self.enter_handle(handle, start);
+        }
+        let handle = handle.map(RequestHandle::key);
+        let mut attributes = attributes();
+        if let Some(req) = req {
+            attributes.extend(request_attributes(req));
+            if let Some(processes) = &mut self.processes {
+                attributes.extend(processes.attributes(req.pid()).iter().cloned());
+            }
+        }
+        if !self.span_filter.min_duration.is_zero() {
+            return Some(OperationSpan::Deferred {
+                name,
+                attributes,
+                handle,
+            });
+        }
+        let span = self.build_span(name, start, attributes, handle);
+        Some(OperationSpan::Started(
+            self.parent_context(handle).with_span(span),
+        ))
+    }
+
+    /// End the span of an operation which ran for `duration` from `start`, and failed with
+    /// `errno` if known, then log the operation if it failed or was slow
+    fn end_operation(
+        &self,
+        operation: &'static str,
+        span: Option<OperationSpan>,
+        start: SystemTime,
+        duration: Duration,
+        errno: Option<c_int>,
+    ) {
+        let cx = match span {
+            Some(OperationSpan::Started(cx)) => Some(cx),
+            Some(OperationSpan::Deferred {
+                name,
+                attributes,
+                handle,
+            }) if duration >= self.span_filter.min_duration || errno.is_some() => {
+                let span = self.build_span(name, start, attributes, handle);
+                Some(self.parent_context(handle).with_span(span))
+            }
+            _ => None,
+        };
+        if let (Some(cx), Some(errno)) = (&cx, errno) {
+            let span = cx.span();
+            span.set_attribute(attribute::errno(errno));
+            let label = attribute::errno_label(errno);
+            span.set_status(Status::error(format!("{operation} failed with {label}")));
+        }
+        if let Some(logs) = &self.logs {
+            let _guard = cx.clone().map(Context::attach);
+            self.log_operation(logs, operation, duration, errno);
+        }
+        if let Some(cx) = cx {
+            cx.span().end_with_timestamp(start + duration);
+        }
+    }
+
+    /// Emit the log records of an operation: an error if it failed, a warning if it was slow
+    fn log_operation(
+        &self,
+        logs: &LogEmitter,
+        operation: &'static str,
+        duration: Duration,
+        errno: Option<c_int>,
+    ) {
+        let attributes = || {
+            vec![
+                KeyValue::new(attribute::OPERATION, operation),
+                KeyValue::new(attribute::DURATION, duration.as_secs_f64()),
+            ]
+        };
+        if let Some(errno) = errno {
+            let mut attributes = attributes();
+            attributes.push(attribute::errno(errno));
+            logs.emit(
+                Severity::Error,
+                format!("{operation} failed with {}", attribute::errno_label(errno)),
+                attributes,
+            );
+        }
+        if self
+            .slow_operation_threshold
+            .is_some_and(|threshold| duration >= threshold)
+        {
+            logs.emit(
+                Severity::Warn,
+                format!("{operation} took {duration:?}"),
+                attributes(),
+            );
+        }
+    }
+
+    fn build_span(
+        &self,
+        name: &'static str,
+        start: SystemTime,
+        attributes: Vec<KeyValue>,
+        handle: Option<(u64, u64)>,
+    ) -> TyTracer::Span {
+        self.tracer
+            .span_builder(name)
+            .with_start_time(start)
+            .with_attributes(attributes)
+            .with_links(self.session_links())
+            .start_with_context(&self.tracer, &self.parent_context(handle))
+    }
+
+    /// Context of the parent of an operation span: the span of its file handle if any,
+    /// otherwise the current context
+    fn parent_context(&self, handle: Option<(u64, u64)>) -> Context {
+        match handle.and_then(|handle| self.handles.get(&handle)) {
+            Some(cx) => cx.clone(),
+            None => Context::current(),
+        }
+    }
+
+    /// Start the `fuse.session` span, the root of the spans of the session
+    fn start_session(&mut self, start: SystemTime) {
+        let session = self
+            .tracer
+            .span_builder("fuse.session")
+            .with_start_time(start)
+            .start_with_context(&self.tracer, &Context::new());
+        self.session = Some(Context::new().with_span(session));
+    }
+
+    /// Links from a span to the session span
+    fn session_links(&self) -> Vec<Link> {
+        match &self.session {
+            Some(session) => vec![Link::with_context(session.span().span_context().clone())],
+            None => Vec::new(),
+        }
+    }
+
+    /// Start the span of a file handle first used by a request started at `start`, if handle
+    /// spans are enabled and it is not started yet
+    fn enter_handle(&mut self, (ino, fh): (u64, u64), start: SystemTime) {
+        if !self.file_handle_spans || self.handles.contains_key(&(ino, fh)) {
+            return;
+        }
+        let span = self
+            .tracer
+            .span_builder("fuse.file_handle")
+            .with_start_time(start)
+            .with_attributes([attribute::ino(ino), attribute::fh(fh)])
+            .with_links(self.session_links())
+            .start_with_context(&self.tracer, &Context::new());
+        self.handles
+            .insert((ino, fh), Context::new().with_span(span));
+    }
+
+    /// End the span of a released file handle
+    fn release_handle(&mut self, handle: (u64, u64), end: SystemTime) {
+        if let Some(cx) = self.handles.remove(&handle) {
+            cx.span().end_with_timestamp(end);
+        }
+    }
+}
+
+/// File handle of a request, as `(ino, fh)`
+#[derive(Clone, Copy)]
+enum RequestHandle {
+    /// Handle used by the request, whose span is started if it is not yet
+    Uses((u64, u64)),
+    /// Handle released by the request, whose span is only ended if it exists
+    Releases((u64, u64)),
+}
+
+impl RequestHandle {
+    fn key(self) -> (u64, u64) {
+        match self {
+            RequestHandle::Uses(handle) | RequestHandle::Releases(handle) => handle,
+        }
+    }
+}
+
+/// Span of an operation, between `start_operation` and `end_operation`
+enum OperationSpan {
+    /// Span started before the call, made current during the call
+    Started(Context),
+    /// Span built after the call, if the call is long enough or failed
+    Deferred {
+        name: &'static str,
+        attributes: Vec<KeyValue>,
+        handle: Option<(u64, u64)>,
+    },
+}
+
+impl OperationSpan {
+    /// Make the span current until the guard is dropped, if it is already started
+    fn attach(&self) -> Option<ContextGuard> {
+        match self {
+            OperationSpan::Started(cx) => Some(cx.clone().attach()),
+            OperationSpan::Deferred { .. } => None,
+        }
+    }
+}
+
+/// Span attributes describing calling processes, by pid
+struct ProcessCache {
+    ttl: Duration,
+    entries: HashMap<u32, (Instant, Vec<KeyValue>)>,
+}
+
+impl ProcessCache {
+    /// Number of cached processes above which expired entries are evicted
+    const EVICTION_THRESHOLD: usize = 1024;
+
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
     }
 
-    fn destroy(&mut self) {
-        let mut span = self.tracer.start("Filesystem::init");
-        self.fs.destroy();
-        span.end();
+    /// Attributes of the process `pid`, read again once older than the TTL since pids get reused
+    fn attributes(&mut self, pid: u32) -> &[KeyValue] {
+        let now = Instant::now();
+        let expired = match self.entries.get(&pid) {
+            Some((read_at, _)) => now.duration_since(*read_at) >= self.ttl,
+            None => true,
+        };
+        if expired {
+            if self.entries.len() >= Self::EVICTION_THRESHOLD {
+                let ttl = self.ttl;
+                self.entries
+                    .retain(|_, (read_at, _)| now.duration_since(*read_at) < ttl);
+            }
+            self.entries.insert(pid, (now, process_attributes(pid)));
+        }
+        &self.entries[&pid].1
+    }
+}
+
+/// Read the attributes of a process from `/proc/<pid>`
+fn process_attributes(pid: u32) -> Vec<KeyValue> {
+    let dir = Path::new("/proc").join(pid.to_string());
+    let mut attributes = Vec::new();
+    if let Ok(comm) = std::fs::read_to_string(dir.join("comm")) {
+        let comm: Arc<str> = comm.trim_end_matches('\n').into();
+        attributes.push(KeyValue::new(attribute::REQUEST_COMM, comm));
     }
+    if let Ok(exe) = std::fs::read_link(dir.join("exe")) {
+        let exe: Arc<str> = exe.to_string_lossy().into();
+        attributes.push(KeyValue::new(attribute::REQUEST_EXE, exe));
+    }
+    if let Some(cgroup) = std::fs::read_to_string(dir.join("cgroup"))
+        .ok()
+        .as_deref()
+        .and_then(parse_cgroup)
+    {
+        let cgroup: Arc<str> = cgroup.into();
+        attributes.push(KeyValue::new(attribute::REQUEST_CGROUP, cgroup));
+    }
+    attributes
+}
 
-    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let mut span = self.tracer.start("Filesystem::lookup");
-        self.fs.lookup(req, parent, name, reply);
-        span.end();
+/// Control group path from the content of `/proc/<pid>/cgroup`: the cgroup v2 path if the
+/// process is in the unified hierarchy, otherwise the path in the first v1 hierarchy
+fn parse_cgroup(content: &str) -> Option<&str> {
+    let mut first = None;
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(hierarchy), Some(controllers), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if hierarchy == "0" && controllers.is_empty() {
+            return Some(path);
+        }
+        first.get_or_insert(path);
     }
+    first
+}
 
-    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
-        let mut span = self.tracer.start("Filesystem::forget");
-        self.fs.forget(req, ino, nlookup);
-        span.end();
+/// Span attributes for the fields of a request
+fn request_attributes(req: &Request<'_>) -> [KeyValue; 4] {
+    [
+        KeyValue::new(attribute::REQUEST_UNIQUE, req.unique() as i64),
+        KeyValue::new(attribute::REQUEST_UID, i64::from(req.uid())),
+        KeyValue::new(attribute::REQUEST_GID, i64::from(req.gid())),
+        KeyValue::new(attribute::REQUEST_PID, i64::from(req.pid())),
+    ]
+}
+
+/// Implement `Filesystem` by forwarding the methods to the inner filesystem and recording a span,
+/// and define `OPERATIONS`, the names of the traced methods.
+///
+/// The macro takes an `impl Filesystem` block with the methods implemented by hand (`init` and
+/// `destroy`), then the other methods. Each of them is declared with its `fuser` signature,
+/// followed by an expression building the operation arguments to record on the span. The span is named `Filesystem::<method>`, and is
+/// the current span while the inner filesystem runs (see `start_operation`).
+///
+/// The attributes may be followed by:
+/// - `handle: (ino, fh)`, the file handle used by the request, to parent its span under the
+///   handle span; or `release_handle: (ino, fh)` to end the handle span after the request, which
+///   does not start one so that handles without other requests get no span
+/// - `read_bytes: n` or `write_bytes: n`, the I/O size to add to the matching metric, evaluated
+///   after the call
+macro_rules! traced_methods {
+    (
+        impl Filesystem { $($untraced:tt)* }
+        $(
+        $(#[doc = $doc:literal])*
+        $(#[cfg($cfg:meta)])?
+        fn $method:ident(&mut self, $req:ident: &Request<'_> $(, $arg:ident: $ty:ty)* $(,)?)
+            => $attributes:expr
+            $(, handle: $handle:expr)?
+            $(, release_handle: $released:expr)?
+            $(, read_bytes: $read_bytes:expr)?
+            $(, write_bytes: $write_bytes:expr)?;
+        )*
+    ) => {
+        /// Names of the operations traced by `OpentelemetryFuser`, after their `Filesystem` method
+        const OPERATIONS: &[&str] = &[
+            "init",
+            "destroy",
+            $($(#[cfg($cfg)])? stringify!($method),)*
+        ];
+
+        impl<TyFs, TyTracer> Filesystem for OpentelemetryFuser<TyFs, TyTracer>
+        where
+            TyFs: Filesystem,
+            TyTracer: Tracer,
+            TyTracer::Span: Send + Sync + 'static,
+        {
+            $($untraced)*
+
+        $(
+            $(#[doc = $doc])*
+            $(#[cfg($cfg)])?
+            fn $method(&mut self, $req: &Request<'_> $(, $arg: $ty)*) {
+                const OPERATION: &str = stringify!($method);
+                let handle: Option<RequestHandle> = None
+                    $(.or(Some(RequestHandle::Uses($handle))))?
+                    $(.or(Some(RequestHandle::Releases($released))))?;
+                let start = SystemTime::now();
+                let span = self.start_operation(
+                    OPERATION,
+                    concat!("Filesystem::", stringify!($method)),
+                    Some($req),
+                    handle,
+                    start,
+                    || $attributes,
+                );
+                let guard = span.as_ref().and_then(OperationSpan::attach);
+                let timer = Instant::now();
+                self.fs.$method($req $(, $arg)*);
+                let duration = timer.elapsed();
+                drop(guard);
+                self.end_operation(OPERATION, span, start, duration, None);
+                $(self.release_handle($released, start + duration);)?
+                if let Some(metrics) = &self.metrics {
+                    metrics.record(OPERATION, None, duration);
+                    $(metrics.read_bytes.add($read_bytes, &[]);)?
+                    $(metrics.write_bytes.add($write_bytes, &[]);)?
+                }
+            }
+        )*
+        }
+    };
+}
+
+traced_methods! {
+    impl Filesystem {
+        fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+            let start = SystemTime::now();
+            self.start_session(start);
+            let span =
+                self.start_operation("init", "Filesystem::init", Some(req), None, start, Vec::new);
+            let guard = span.as_ref().and_then(OperationSpan::attach);
+            let timer = Instant::now();
+            let res = self.fs.init(req, config);
+            let duration = timer.elapsed();
+            drop(guard);
+            // Only `init` reports its outcome to the wrapper: the other operations answer through
+            // their `Reply*` object, which is opaque to us.
+            self.end_operation("init", span, start, duration, res.err());
+            if let Some(metrics) = &self.metrics {
+                let outcome = if res.is_ok() { "ok" } else { "error" };
+                metrics.record("init", Some(outcome), duration);
+            }
+            res
+        }
+
+        fn destroy(&mut self) {
+            let start = SystemTime::now();
+            // `destroy` has no request: it is sampled as if its unique id was 0
+            let span = self.start_operation(
+                "destroy",
+                "Filesystem::destroy",
+                None,
+                None,
+                start,
+                Vec::new,
+            );
+            let guard = span.as_ref().and_then(OperationSpan::attach);
+            let timer = Instant::now();
+            self.fs.destroy();
+            let duration = timer.elapsed();
+            drop(guard);
+            self.end_operation("destroy", span, start, duration, None);
+            let end = start + duration;
+            for (_, handle) in self.handles.drain() {
+                handle.span().end_with_timestamp(end);
+            }
+            if let Some(session) = self.session.take() {
+                session.span().end_with_timestamp(end);
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.record("destroy", None, duration);
+            }
+        }
     }
 
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry)
+        => vec![attribute::parent(parent), attribute::name(name)];
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64)
+        => vec![attribute::ino(ino)];
+
     /// Like forget, but take multiple forget requests at once for performance. The default
     /// implementation will fallback to forget.
-    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[fuse_forget_one]) {
-        let mut span = self.tracer.start("Filesystem::batch_forget");
-        self.fs.batch_forget(req, nodes);
-        span.end();
-    }
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[fuse_forget_one])
+        => Vec::new();
 
     /// Get file attributes.
-    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        // warn!("[Not Implemented] getattr(ino: {:#x?})", ino);
-        let mut span = self.tracer.start("Filesystem::getattr");
-        self.fs.getattr(req, ino, reply);
-        span.end();
-    }
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr)
+        => vec![attribute::ino(ino)];
 
     /// Set file attributes.
     fn setattr(
@@ -92,29 +891,17 @@ where
         bkuptime: Option<SystemTime>,
         flags: Option<u32>,
         reply: ReplyAttr,
-    ) {
-        // debug!(
-        //     "[Not Implemented] setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
-        //     gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
-        //     ino, mode, uid, gid, size, fh, flags
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::setattr");
-        self.fs.setattr(
-            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
-            flags, reply,
-        );
-        span.end();
-    }
+    ) => {
+        let mut attributes = vec![attribute::ino(ino)];
+        attributes.extend(size.map(attribute::size));
+        attributes.extend(fh.map(attribute::fh));
+        attributes.extend(flags.map(|flags| attribute::flags(i64::from(flags))));
+        attributes
+    };
 
     /// Read symbolic link.
-    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
-        // debug!("[Not Implemented] readlink(ino: {:#x?})", ino);
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::readlink");
-        self.fs.readlink(req, ino, reply);
-        span.end();
-    }
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData)
+        => vec![attribute::ino(ino)];
 
     /// Create file node.
     /// Create a regular file, character device, block device, fifo or socket node.
@@ -127,17 +914,7 @@ where
         umask: u32,
         rdev: u32,
         reply: ReplyEntry,
-    ) {
-        // debug!(
-        //     "[Not Implemented] mknod(parent: {:#x?}, name: {:?}, mode: {}, \
-        //     umask: {:#x?}, rdev: {})",
-        //     parent, name, mode, umask, rdev
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::mknod");
-        self.fs.mknod(req, parent, name, mode, umask, rdev, reply);
-        span.end();
-    }
+    ) => vec![attribute::parent(parent), attribute::name(name)];
 
     /// Create a directory.
     fn mkdir(
@@ -148,40 +925,15 @@ where
         mode: u32,
         umask: u32,
         reply: ReplyEntry,
-    ) {
-        // debug!(
-        //     "[Not Implemented] mkdir(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?})",
-        //     parent, name, mode, umask
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::mkdir");
-        self.fs.mkdir(req, parent, name, mode, umask, reply);
-        span.end();
-    }
+    ) => vec![attribute::parent(parent), attribute::name(name)];
 
     /// Remove a file.
-    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        // debug!(
-        //     "[Not Implemented] unlink(parent: {:#x?}, name: {:?})",
-        //     parent, name,
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::unlink");
-        self.fs.unlink(req, parent, name, reply);
-        span.end();
-    }
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty)
+        => vec![attribute::parent(parent), attribute::name(name)];
 
     /// Remove a directory.
-    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        // debug!(
-        //     "[Not Implemented] rmdir(parent: {:#x?}, name: {:?})",
-        //     parent, name,
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::rmdir");
-        self.fs.rmdir(req, parent, name, reply);
-        span.end();
-    }
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty)
+        => vec![attribute::parent(parent), attribute::name(name)];
 
     /// Create a symbolic link.
     fn symlink(
@@ -191,16 +943,7 @@ where
         link_name: &OsStr,
         target: &Path,
         reply: ReplyEntry,
-    ) {
-        // debug!(
-        //     "[Not Implemented] symlink(parent: {:#x?}, link_name: {:?}, target: {:?})",
-        //     parent, link_name, target,
-        // );
-        // reply.error(EPERM);
-        let mut span = self.tracer.start("Filesystem::symlink");
-        self.fs.symlink(req, parent, link_name, target, reply);
-        span.end();
-    }
+    ) => vec![attribute::parent(parent), attribute::name(link_name)];
 
     /// Rename a file.
     fn rename(
@@ -212,18 +955,13 @@ where
         newname: &OsStr,
         flags: u32,
         reply: ReplyEmpty,
-    ) {
-        // debug!(
-        //     "[Not Implemented] rename(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \
-        //     newname: {:?}, flags: {})",
-        //     parent, name, newparent, newname, flags,
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::rename");
-        self.fs
-            .rename(req, parent, name, newparent, newname, flags, reply);
-        span.end();
-    }
+    ) => vec![
+        attribute::parent(parent),
+        attribute::name(name),
+        attribute::new_parent(newparent),
+        attribute::new_name(newname),
+        attribute::flags(i64::from(flags)),
+    ];
 
     /// Create a hard link.
     fn link(
@@ -233,16 +971,11 @@ where
         newparent: u64,
         newname: &OsStr,
         reply: ReplyEntry,
-    ) {
-        // debug!(
-        //     "[Not Implemented] link(ino: {:#x?}, newparent: {:#x?}, newname: {:?})",
-        //     ino, newparent, newname
-        // );
-        // reply.error(EPERM);
-        let mut span = self.tracer.start("Filesystem::link");
-        self.fs.link(req, ino, newparent, newname, reply);
-        span.end();
-    }
+    ) => vec![
+        attribute::ino(ino),
+        attribute::new_parent(newparent),
+        attribute::new_name(newname),
+    ];
 
     /// Open a file.
     /// Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are
@@ -252,12 +985,8 @@ where
     /// anything in fh. There are also some flags (direct_io, keep_cache) which the
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details.
-    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        // reply.opened(0, 0);
-        let mut span = self.tracer.start("Filesystem::open");
-        self.fs.open(req, ino, flags, reply);
-        span.end();
-    }
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen)
+        => vec![attribute::ino(ino), attribute::flags(i64::from(flags))];
 
     /// Read data.
     /// Read should send exactly the number of bytes requested except on EOF or error,
@@ -279,18 +1008,13 @@ where
         flags: i32,
         lock_owner: Option<u64>,
         reply: ReplyData,
-    ) {
-        // warn!(
-        //     "[Not Implemented] read(ino: {:#x?}, fh: {}, offset: {}, size: {}, \
-        //     flags: {:#x?}, lock_owner: {:?})",
-        //     ino, fh, offset, size, flags, lock_owner
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::read");
-        self.fs
-            .read(req, ino, fh, offset, size, flags, lock_owner, reply);
-        span.end();
-    }
+    ) => vec![
+        attribute::ino(ino),
+        attribute::fh(fh),
+        attribute::offset(offset),
+        attribute::size(u64::from(size)),
+        attribute::flags(i64::from(flags)),
+    ], handle: (ino, fh), read_bytes: u64::from(size);
 
     /// Write data.
     /// Write should return exactly the number of bytes requested except on error. An
@@ -299,9 +1023,9 @@ where
     /// value of this operation. fh will contain the value set by the open method, or
     /// will be undefined if the open method didn't set any value.
     ///
-    /// write_flags: will contain FUSE_WRITE_CACHE, if this write is from the page cache. If set,
-    /// the pid, uid, gid, and fh may not match the value that would have been sent if write cachin
-    /// is disabled
+    /// write_flags: will contain FUSE_WRITE_CACHE, if this write is from the page cache. If
+    /// set, the pid, uid, gid, and fh may not match the value that would have been sent if
+    /// write cachin is disabled
     /// flags: these are the file flags, such as O_SYNC. Only supported with ABI >= 7.9
     /// lock_owner: only supported with ABI >= 7.9
     fn write(
@@ -315,33 +1039,13 @@ where
         flags: i32,
         lock_owner: Option<u64>,
         reply: ReplyWrite,
-    ) {
-        // debug!(
-        //     "[Not Implemented] write(ino: {:#x?}, fh: {}, offset: {}, data.len(): {}, \
-        //     write_flags: {:#x?}, flags: {:#x?}, lock_owner: {:?})",
-        //     ino,
-        //     fh,
-        //     offset,
-        //     data.len(),
-        //     write_flags,
-        //     flags,
-        //     lock_owner
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::write");
-        self.fs.write(
-            req,
-            ino,
-            fh,
-            offset,
-            data,
-            write_flags,
-            flags,
-            lock_owner,
-            reply,
-        );
-        span.end();
-    }
+    ) => vec![
+        attribute::ino(ino),
+        attribute::fh(fh),
+        attribute::offset(offset),
+        attribute::size(data.len() as u64),
+        attribute::flags(i64::from(flags)),
+    ], handle: (ino, fh), write_bytes: data.len() as u64;
 
     /// Flush method.
     /// This is called on each close() of the opened file. Since file descriptors can
@@ -353,16 +1057,14 @@ where
     /// is not forced to flush pending writes. One reason to flush data, is if the
     /// filesystem wants to return write errors. If the filesystem supports file locking
     /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
-    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
-        // debug!(
-        //     "[Not Implemented] flush(ino: {:#x?}, fh: {}, lock_owner: {:?})",
-        //     ino, fh, lock_owner
-        // );
-        // reply.error(ENOSYS);
-        let mut span = self.tracer.start("Filesystem::flush");
-        self.fs.flush(req, ino, fh, lock_owner, reply);
-        span.end();
-    }
+    fn flush(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) => vec![attribute::ino(ino), attribute::fh(fh)], handle: (ino, fh);
 
     /// Release an open file.
     /// Release is called when there are no more references to an open file: all file
@@ -381,23 +1083,14 @@ where
         lock_owner: Option<u64>,
         flush: bool,
         reply: ReplyEmpty,
-    ) {
-        // reply.ok();
-        self.fs
-            .release(req, ino, fh, flags, lock_owner, flush, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh), attribute::flags(i64::from(flags))],
+        release_handle: (ino, fh);
 
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
-    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
-        // debug!(
-        //     "[Not Implemented] fsync(ino: {:#x?}, fh: {}, datasync: {})",
-        //     ino, fh, datasync
-        // );
-        // reply.error(ENOSYS);
-        self.fs.fsync(req, ino, fh, datasync, reply)
-    }
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty)
+        => vec![attribute::ino(ino), attribute::fh(fh)], handle: (ino, fh);
 
     /// Open a directory.
     /// Filesystem may store an arbitrary file handle (pointer, index, etc) in fh, and
@@ -406,10 +1099,8 @@ where
     /// anything in fh, though that makes it impossible to implement standard conforming
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
-    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        // reply.opened(0, 0);
-        self.fs.opendir(req, ino, flags, reply)
-    }
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen)
+        => vec![attribute::ino(ino), attribute::flags(i64::from(flags))];
 
     /// Read directory.
     /// Send a buffer filled using buffer.fill(), with size not exceeding the
@@ -423,14 +1114,8 @@ where
         fh: u64,
         offset: i64,
         reply: ReplyDirectory,
-    ) {
-        // warn!(
-        //     "[Not Implemented] readdir(ino: {:#x?}, fh: {}, offset: {})",
-        //     ino, fh, offset
-        // );
-        // reply.error(ENOSYS);
-        self.fs.readdir(req, ino, fh, offset, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh), attribute::offset(offset)],
+        handle: (ino, fh);
 
     /// Read directory.
     /// Send a buffer filled using buffer.fill(), with size not exceeding the
@@ -444,23 +1129,22 @@ where
         fh: u64,
         offset: i64,
         reply: ReplyDirectoryPlus,
-    ) {
-        // debug!(
-        //     "[Not Implemented] readdirplus(ino: {:#x?}, fh: {}, offset: {})",
-        //     ino, fh, offset
-        // );
-        // reply.error(ENOSYS);
-        self.fs.readdirplus(req, ino, fh, offset, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh), attribute::offset(offset)],
+        handle: (ino, fh);
 
     /// Release an open directory.
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
     /// opendir method didn't set any value.
-    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
-        // reply.ok();
-        self.fs.releasedir(req, ino, fh, flags, reply)
-    }
+    fn releasedir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        reply: ReplyEmpty,
+    ) => vec![attribute::ino(ino), attribute::fh(fh), attribute::flags(i64::from(flags))],
+        release_handle: (ino, fh);
 
     /// Synchronize directory contents.
     /// If the datasync parameter is set, then only the directory contents should
@@ -473,20 +1157,11 @@ where
         fh: u64,
         datasync: bool,
         reply: ReplyEmpty,
-    ) {
-        // debug!(
-        //     "[Not Implemented] fsyncdir(ino: {:#x?}, fh: {}, datasync: {})",
-        //     ino, fh, datasync
-        // );
-        // reply.error(ENOSYS);
-        self.fs.fsyncdir(req, ino, fh, datasync, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh)], handle: (ino, fh);
 
     /// Get file system statistics.
-    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
-        // reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
-        self.fs.statfs(req, ino, reply)
-    }
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs)
+        => vec![attribute::ino(ino)];
 
     /// Set an extended attribute.
     fn setxattr(
@@ -498,15 +1173,12 @@ where
         flags: i32,
         position: u32,
         reply: ReplyEmpty,
-    ) {
-        // debug!(
-        //     "[Not Implemented] setxattr(ino: {:#x?}, name: {:?}, flags: {:#x?}, position: {})",
-        //     ino, name, flags, position
-        // );
-        // reply.error(ENOSYS);
-        self.fs
-            .setxattr(req, ino, name, value, flags, position, reply)
-    }
+    ) => vec![
+        attribute::ino(ino),
+        attribute::name(name),
+        attribute::size(value.len() as u64),
+        attribute::flags(i64::from(flags)),
+    ];
 
     /// Get an extended attribute.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
@@ -519,47 +1191,29 @@ where
         name: &OsStr,
         size: u32,
         reply: ReplyXattr,
-    ) {
-        // debug!(
-        //     "[Not Implemented] getxattr(ino: {:#x?}, name: {:?}, size: {})",
-        //     ino, name, size
-        // );
-        // reply.error(ENOSYS);
-        self.fs.getxattr(req, ino, name, size, reply)
-    }
+    ) => vec![
+        attribute::ino(ino),
+        attribute::name(name),
+        attribute::size(u64::from(size)),
+    ];
 
     /// List extended attribute names.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
-        // debug!(
-        //     "[Not Implemented] listxattr(ino: {:#x?}, size: {})",
-        //     ino, size
-        // );
-        // reply.error(ENOSYS);
-        self.fs.listxattr(req, ino, size, reply)
-    }
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr)
+        => vec![attribute::ino(ino), attribute::size(u64::from(size))];
 
     /// Remove an extended attribute.
-    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
-        // debug!(
-        //     "[Not Implemented] removexattr(ino: {:#x?}, name: {:?})",
-        //     ino, name
-        // );
-        // reply.error(ENOSYS);
-        self.fs.removexattr(req, ino, name, reply)
-    }
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty)
+        => vec![attribute::ino(ino), attribute::name(name)];
 
     /// Check file access permissions.
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
-    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
-        // debug!("[Not Implemented] access(ino: {:#x?}, mask: {})", ino, mask);
-        // reply.error(ENOSYS);
-        self.fs.access(req, ino, mask, reply)
-    }
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty)
+        => vec![attribute::ino(ino)];
 
     /// Create and open a file.
     /// If the file does not exist, first create it with the specified mode, and then
@@ -580,15 +1234,11 @@ where
         umask: u32,
         flags: i32,
         reply: ReplyCreate,
-    ) {
-        // debug!(
-        //     "[Not Implemented] create(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?}, \
-        //     flags: {:#x?})",
-        //     parent, name, mode, umask, flags
-        // );
-        // reply.error(ENOSYS);
-        self.fs.create(req, parent, name, mode, umask, flags, reply)
-    }
+    ) => vec![
+        attribute::parent(parent),
+        attribute::name(name),
+        attribute::flags(i64::from(flags)),
+    ];
 
     /// Test for a POSIX file lock.
     fn getlk(
@@ -602,16 +1252,7 @@ where
         typ: i32,
         pid: u32,
         reply: ReplyLock,
-    ) {
-        // debug!(
-        //     "[Not Implemented] getlk(ino: {:#x?}, fh: {}, lock_owner: {}, start: {}, \
-        //     end: {}, typ: {}, pid: {})",
-        //     ino, fh, lock_owner, start, end, typ, pid
-        // );
-        // reply.error(ENOSYS);
-        self.fs
-            .getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh)], handle: (ino, fh);
 
     /// Acquire, modify or release a POSIX file lock.
     /// For POSIX threads (NPTL) there's a 1-1 relation between pid and owner, but
@@ -632,28 +1273,13 @@ where
         pid: u32,
         sleep: bool,
         reply: ReplyEmpty,
-    ) {
-        // debug!(
-        //     "[Not Implemented] setlk(ino: {:#x?}, fh: {}, lock_owner: {}, start: {}, \
-        //     end: {}, typ: {}, pid: {}, sleep: {})",
-        //     ino, fh, lock_owner, start, end, typ, pid, sleep
-        // );
-        // reply.error(ENOSYS);
-        self.fs
-            .setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh)], handle: (ino, fh);
 
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
-    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
-        // debug!(
-        //     "[Not Implemented] bmap(ino: {:#x?}, blocksize: {}, idx: {})",
-        //     ino, blocksize, idx,
-        // );
-        // reply.error(ENOSYS);
-        self.fs.bmap(req, ino, blocksize, idx, reply)
-    }
+    fn bmap(&mut self, req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap)
+        => vec![attribute::ino(ino)];
 
     /// control device
     fn ioctl(
@@ -666,21 +1292,8 @@ where
         in_data: &[u8],
         out_size: u32,
         reply: ReplyIoctl,
-    ) {
-        // debug!(
-        //     "[Not Implemented] ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {}, \
-        //     in_data.len(): {}, out_size: {})",
-        //     ino,
-        //     fh,
-        //     flags,
-        //     cmd,
-        //     in_data.len(),
-        //     out_size,
-        // );
-        // reply.error(ENOSYS);
-        self.fs
-            .ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh), attribute::flags(i64::from(flags))],
+        handle: (ino, fh);
 
     /// Poll for events
     fn poll(
@@ -692,14 +1305,8 @@ where
         events: u32,
         flags: u32,
         reply: ReplyPoll,
-    ) {
-        // debug!(
-        //     "[Not Implemented] poll(ino: {:#x?}, fh: {}, kh: {}, events: {}, flags: {})",
-        //     ino, fh, kh, events, flags
-        // );
-        // reply.error(ENOSYS);
-        self.fs.poll(req, ino, fh, kh, events, flags, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh), attribute::flags(i64::from(flags))],
+        handle: (ino, fh);
 
     /// Preallocate or deallocate space to a file
     fn fallocate(
@@ -711,15 +1318,12 @@ where
         length: i64,
         mode: i32,
         reply: ReplyEmpty,
-    ) {
-        // debug!(
-        //     "[Not Implemented] fallocate(ino: {:#x?}, fh: {}, offset: {}, \
-        //     length: {}, mode: {})",
-        //     ino, fh, offset, length, mode
-        // );
-        // reply.error(ENOSYS);
-        self.fs.fallocate(req, ino, fh, offset, length, mode, reply)
-    }
+    ) => vec![
+        attribute::ino(ino),
+        attribute::fh(fh),
+        attribute::offset(offset),
+        attribute::size(length as u64),
+    ], handle: (ino, fh);
 
     /// Reposition read/write file offset
     fn lseek(
@@ -730,16 +1334,12 @@ where
         offset: i64,
         whence: i32,
         reply: ReplyLseek,
-    ) {
-        // debug!(
-        //     "[Not Implemented] lseek(ino: {:#x?}, fh: {}, offset: {}, whence: {})",
-        //     ino, fh, offset, whence
-        // );
-        // reply.error(ENOSYS);
-        self.fs.lseek(req, ino, fh, offset, whence, reply)
-    }
+    ) => vec![attribute::ino(ino), attribute::fh(fh), attribute::offset(offset)],
+        handle: (ino, fh);
 
     /// Copy the specified range from the source inode to the destination inode
+    ///
+    /// The span records the source inode, handle and offset.
     fn copy_file_range(
         &mut self,
         req: &Request<'_>,
@@ -752,27 +1352,19 @@ where
         len: u64,
         flags: u32,
         reply: ReplyWrite,
-    ) {
-        // debug!(
-        //     "[Not Implemented] copy_file_range(ino_in: {:#x?}, fh_in: {}, \
-        //     offset_in: {}, ino_out: {:#x?}, fh_out: {}, offset_out: {}, \
-        //     len: {}, flags: {})",
-        //     ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags
-        // );
-        // reply.error(ENOSYS);
-        self.fs.copy_file_range(
-            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
-        )
-    }
+    ) => vec![
+        attribute::ino(ino_in),
+        attribute::fh(fh_in),
+        attribute::offset(offset_in),
+        attribute::size(len),
+        attribute::flags(i64::from(flags)),
+    ], handle: (ino_in, fh_in);
 
     /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
-    fn setvolname(&mut self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
-        // debug!("[Not Implemented] setvolname(name: {:?})", name);
-        // reply.error(ENOSYS);
-        self.fs.setvolname(req, name, reply)
-    }
+    fn setvolname(&mut self, req: &Request<'_>, name: &OsStr, reply: ReplyEmpty)
+        => vec![attribute::name(name)];
 
     /// macOS only (undocumented)
     #[cfg(target_os = "macos")]
@@ -785,23 +1377,544 @@ where
         newname: &OsStr,
         options: u64,
         reply: ReplyEmpty,
-    ) {
-        // debug!(
-        //     "[Not Implemented] exchange(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \
-        //     newname: {:?}, options: {})",
-        //     parent, name, newparent, newname, options
-        // );
-        // reply.error(ENOSYS);
-        self.fs
-            .exchange(req, parent, name, newparent, newname, options, reply)
-    }
+    ) => vec![
+        attribute::parent(parent),
+        attribute::name(name),
+        attribute::new_parent(newparent),
+        attribute::new_name(newname),
+    ];
 
     /// macOS only: Query extended times (bkuptime and crtime). Set fuse_init_out.flags
     /// during init to FUSE_XTIMES to enable
     #[cfg(target_os = "macos")]
-    fn getxtimes(&mut self, req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
-        // debug!("[Not Implemented] getxtimes(ino: {:#x?})", ino);
-        // reply.error(ENOSYS);
-        self.fs.getxtimes(req, ino, reply)
+    fn getxtimes(&mut self, req: &Request<'_>, ino: u64, reply: ReplyXTimes)
+        => vec![attribute::ino(ino)];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::logs::AnyValue;
+    use opentelemetry::metrics::Result as MetricsResult;
+    use opentelemetry::trace::{SpanContext, TraceResult};
+    use opentelemetry::Key;
+    use opentelemetry_sdk::export::trace::SpanData;
+    use opentelemetry_sdk::metrics::data::{self, Metric, ResourceMetrics, Sum, Temporality};
+    use opentelemetry_sdk::metrics::reader::{
+        AggregationSelector, MetricReader, TemporalitySelector,
+    };
+    use opentelemetry_sdk::metrics::{
+        Aggregation, InstrumentKind, ManualReader, Pipeline, SdkMeterProvider,
+    };
+    use opentelemetry_sdk::trace::SpanProcessor;
+    use opentelemetry_sdk::Resource;
+    use std::borrow::Cow;
+    use std::sync::{Arc, Mutex, Weak};
+
+    /// Filesystem recording the span context current when `destroy` is called
+    #[derive(Default)]
+    struct CurrentSpanFs {
+        seen: Arc<Mutex<Option<SpanContext>>>,
+    }
+
+    impl Filesystem for CurrentSpanFs {
+        fn destroy(&mut self) {
+            let cx = Context::current();
+            *self.seen.lock().unwrap() = Some(cx.span().span_context().clone());
+        }
+    }
+
+    #[test]
+    fn span_is_current_during_inner_call() {
+        let processor = RecordingProcessor::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_span_processor(processor.clone())
+            .build();
+        let inner = CurrentSpanFs::default();
+        let seen = Arc::clone(&inner.seen);
+        let mut fs = OpentelemetryFuser::new_with_provider(inner, &provider);
+        fs.destroy();
+        let seen = seen.lock().unwrap().clone().unwrap();
+        let spans = processor.spans.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "Filesystem::destroy");
+        assert_eq!(seen.span_id(), spans[0].span_context.span_id());
+        assert!(!Context::current().has_active_span());
+    }
+
+    #[test]
+    fn deferred_span_is_not_current_during_inner_call() {
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let inner = CurrentSpanFs::default();
+        let seen = Arc::clone(&inner.seen);
+        let mut fs = OpentelemetryFuser::new_with_provider(inner, &provider)
+            .with_min_span_duration(Duration::from_millis(1));
+        fs.destroy();
+        let seen = seen.lock().unwrap().clone().unwrap();
+        assert!(!seen.is_valid());
+    }
+
+    /// Manual metric reader that stays readable after being given to the meter provider
+    #[derive(Clone, Debug, Default)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl TemporalitySelector for SharedReader {
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    impl AggregationSelector for SharedReader {
+        fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+            self.0.aggregation(kind)
+        }
+    }
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn collect(&self, rm: &mut ResourceMetrics) -> MetricsResult<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self) -> MetricsResult<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> MetricsResult<()> {
+            self.0.shutdown()
+        }
+    }
+
+    impl SharedReader {
+        /// Collected metric named `name`
+        fn metric(&self, name: &str) -> Metric {
+            let mut rm = ResourceMetrics {
+                resource: Resource::empty(),
+                scope_metrics: Vec::new(),
+            };
+            self.collect(&mut rm).unwrap();
+            rm.scope_metrics
+                .into_iter()
+                .flat_map(|scope| scope.metrics)
+                .find(|metric| metric.name == name)
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn destroy_is_recorded_in_metrics() {
+        let reader = SharedReader::default();
+        let meters = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let mut fs = OpentelemetryFuser::new_with_provider(CurrentSpanFs::default(), &provider)
+            .with_meter_provider(&meters);
+        fs.destroy();
+
+        let operation = KeyValue::new(attribute::OPERATION, "destroy");
+        let operations = reader.metric("fuse.operations");
+        let operations = operations.data.as_any().downcast_ref::<Sum<u64>>().unwrap();
+        assert_eq!(operations.data_points.len(), 1);
+        let point = &operations.data_points[0];
+        assert_eq!(point.value, 1);
+        assert!(point
+            .attributes
+            .iter()
+            .eq([(&operation.key, &operation.value)]));
+
+        let duration = reader.metric("fuse.operation.duration");
+        let duration = duration
+            .data
+            .as_any()
+            .downcast_ref::<data::Histogram<f64>>()
+            .unwrap();
+        assert_eq!(duration.data_points.len(), 1);
+        assert_eq!(duration.data_points[0].count, 1);
+    }
+
+    /// Span processor keeping the ended spans
+    #[derive(Clone, Debug, Default)]
+    struct RecordingProcessor {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl SpanProcessor for RecordingProcessor {
+        fn on_start(&self, _span: &mut opentelemetry_sdk::trace::Span, _cx: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            self.spans.lock().unwrap().push(span);
+        }
+
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fast_failed_operation_keeps_deferred_span() {
+        let processor = RecordingProcessor::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_span_processor(processor.clone())
+            .build();
+        let fs = OpentelemetryFuser::new_with_provider(CurrentSpanFs::default(), &provider)
+            .with_min_span_duration(Duration::from_secs(1));
+        let deferred = || {
+            Some(OperationSpan::Deferred {
+                name: "Filesystem::init",
+                attributes: Vec::new(),
+                handle: None,
+            })
+        };
+        let start = SystemTime::now();
+        fs.end_operation("init", deferred(), start, Duration::ZERO, None);
+        assert!(processor.spans.lock().unwrap().is_empty());
+
+        fs.end_operation("init", deferred(), start, Duration::ZERO, Some(libc::EIO));
+        let spans = processor.spans.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].status, Status::error("init failed with EIO"));
+        assert!(spans[0]
+            .attributes
+            .contains(&KeyValue::new(attribute::ERRNO, "EIO")));
+    }
+
+    /// Filesystem whose `destroy` takes the given time
+    struct SlowFs(Duration);
+
+    impl Filesystem for SlowFs {
+        fn destroy(&mut self) {
+            std::thread::sleep(self.0);
+        }
+    }
+
+    /// Log record kept by `RecordingLogger`
+    #[derive(Default)]
+    struct RecordedLog {
+        severity: Option<Severity>,
+        attributes: Vec<(Key, AnyValue)>,
+        /// Whether a valid span was current when the record was emitted
+        in_span: bool,
+    }
+
+    impl LogRecord for RecordedLog {
+        fn set_timestamp(&mut self, _timestamp: SystemTime) {}
+
+        fn set_observed_timestamp(&mut self, _timestamp: SystemTime) {}
+
+        fn set_severity_text(&mut self, _text: Cow<'static, str>) {}
+
+        fn set_severity_number(&mut self, number: Severity) {
+            self.severity = Some(number);
+        }
+
+        fn set_body(&mut self, _body: AnyValue) {}
+
+        fn add_attributes<I, K, V>(&mut self, attributes: I)
+        where
+            I: IntoIterator<Item = (K, V)>,
+            K: Into<Key>,
+            V: Into<AnyValue>,
+        {
+            for (key, value) in attributes {
+                self.add_attribute(key, value);
+            }
+        }
+
+        fn add_attribute<K, V>(&mut self, key: K, value: V)
+        where
+            K: Into<Key>,
+            V: Into<AnyValue>,
+        {
+            self.attributes.push((key.into(), value.into()));
+        }
+    }
+
+    /// Logger keeping the emitted records of at least `min_severity`
+    #[derive(Clone)]
+    struct RecordingLogger {
+        records: Arc<Mutex<Vec<RecordedLog>>>,
+        min_severity: Severity,
+    }
+
+    impl Default for RecordingLogger {
+        fn default() -> Self {
+            Self {
+                records: Arc::default(),
+                min_severity: Severity::Trace,
+            }
+        }
+    }
+
+    impl Logger for RecordingLogger {
+        type LogRecord = RecordedLog;
+
+        fn create_log_record(&self) -> RecordedLog {
+            RecordedLog::default()
+        }
+
+        fn emit(&self, mut record: RecordedLog) {
+            record.in_span = Context::current().span().span_context().is_valid();
+            self.records.lock().unwrap().push(record);
+        }
+
+        fn event_enabled(&self, level: Severity, _target: &str) -> bool {
+            level >= self.min_severity
+        }
+    }
+
+    #[test]
+    fn slow_operation_is_logged_in_its_span() {
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let logger = RecordingLogger::default();
+        let mut fs =
+            OpentelemetryFuser::new_with_provider(SlowFs(Duration::from_millis(20)), &provider)
+                .with_logger(logger.clone())
+                .with_slow_operation_threshold(Duration::from_millis(10));
+        fs.destroy();
+
+        let records = logger.records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.severity, Some(Severity::Warn));
+        assert!(record.in_span);
+        assert!(record
+            .attributes
+            .contains(&(Key::new(attribute::OPERATION), AnyValue::from("destroy"))));
+        assert!(record
+            .attributes
+            .iter()
+            .any(|(key, _)| key.as_str() == attribute::DURATION));
+    }
+
+    #[test]
+    fn disabled_severity_is_not_logged() {
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let logger = RecordingLogger {
+            min_severity: Severity::Error,
+            ..RecordingLogger::default()
+        };
+        let mut fs = OpentelemetryFuser::new_with_provider(SlowFs(Duration::ZERO), &provider)
+            .with_logger(logger.clone())
+            .with_slow_operation_threshold(Duration::ZERO);
+        fs.destroy();
+        assert!(logger.records.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fast_operation_is_not_logged() {
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let logger = RecordingLogger::default();
+        let mut fs = OpentelemetryFuser::new_with_provider(SlowFs(Duration::ZERO), &provider)
+            .with_logger(logger.clone())
+            .with_slow_operation_threshold(Duration::from_secs(1));
+        fs.destroy();
+        assert!(logger.records.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn operation_span_is_child_of_handle_span() {
+        for min_duration in [Duration::ZERO, Duration::from_millis(1)] {
+            let processor = RecordingProcessor::default();
+            let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+                .with_span_processor(processor.clone())
+                .build();
+            let mut fs = OpentelemetryFuser::new_with_provider(CurrentSpanFs::default(), &provider)
+                .with_file_handle_spans(true)
+                .with_min_span_duration(min_duration);
+            let start = SystemTime::now();
+            let span = fs.start_operation(
+                "read",
+                "Filesystem::read",
+                None,
+                Some(RequestHandle::Uses((1, 2))),
+                start,
+                Vec::new,
+            );
+            fs.end_operation("read", span, start, Duration::from_secs(1), None);
+            assert_eq!(processor.spans.lock().unwrap().len(), 1);
+
+            fs.release_handle((1, 2), start + Duration::from_secs(1));
+            let spans = processor.spans.lock().unwrap();
+            assert_eq!(spans.len(), 2);
+            let (read, handle) = (&spans[0], &spans[1]);
+            assert_eq!(read.name, "Filesystem::read");
+            assert_eq!(handle.name, "fuse.file_handle");
+            assert_eq!(read.parent_span_id, handle.span_context.span_id());
+            assert_eq!(read.span_context.trace_id(), handle.span_context.trace_id());
+        }
+    }
+
+    #[test]
+    fn unsampled_request_starts_no_handle_span() {
+        let processor = RecordingProcessor::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_span_processor(processor.clone())
+            .build();
+        let mut fs = OpentelemetryFuser::new_with_provider(CurrentSpanFs::default(), &provider)
+            .with_file_handle_spans(true)
+            .with_operation_traced("read", false);
+        let start = SystemTime::now();
+        let span = fs.start_operation(
+            "read",
+            "Filesystem::read",
+            None,
+            Some(RequestHandle::Uses((1, 2))),
+            start,
+            Vec::new,
+        );
+        assert!(span.is_none());
+        assert!(fs.handles.is_empty());
+    }
+
+    #[test]
+    fn destroy_ends_handle_and_session_spans() {
+        let processor = RecordingProcessor::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_span_processor(processor.clone())
+            .build();
+        let mut fs = OpentelemetryFuser::new_with_provider(CurrentSpanFs::default(), &provider)
+            .with_file_handle_spans(true);
+        let start = SystemTime::now();
+        fs.start_session(start);
+        let span = fs.start_operation(
+            "read",
+            "Filesystem::read",
+            None,
+            Some(RequestHandle::Uses((1, 2))),
+            start,
+            Vec::new,
+        );
+        fs.end_operation("read", span, start, Duration::ZERO, None);
+        fs.destroy();
+        assert!(fs.handles.is_empty());
+
+        let spans = processor.spans.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|span| span.name.as_ref()).collect();
+        assert_eq!(
+            names,
+            [
+                "Filesystem::read",
+                "Filesystem::destroy",
+                "fuse.file_handle",
+                "fuse.session"
+            ]
+        );
+        let session = spans[3].span_context.clone();
+        for span in &spans[..3] {
+            assert_eq!(span.links.links.len(), 1, "{}", span.name);
+            assert_eq!(span.links.links[0].span_context, session, "{}", span.name);
+        }
+    }
+
+    #[test]
+    fn sample_respects_ratio() {
+        let sampled = (0..10_000).filter(|unique| sample(*unique, 0.25)).count();
+        assert!((2_250..2_750).contains(&sampled), "sampled {sampled}");
+        assert!((0..1_000).all(|unique| sample(unique, 1.0)));
+        assert!((0..1_000).all(|unique| !sample(unique, 0.0)));
+    }
+
+    #[test]
+    fn span_filter_samples_configured_operations() {
+        let mut filter = SpanFilter::default();
+        filter.sampling_ratios.insert("getattr".to_string(), 0.0);
+        assert!(!filter.samples("getattr", 1));
+        assert!(filter.samples("lookup", 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown FUSE operation `get_attr`")]
+    fn unknown_operation_is_rejected() {
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let _ = OpentelemetryFuser::new_with_provider(CurrentSpanFs::default(), &provider)
+            .with_operation_traced("get_attr", false);
+    }
+
+    #[test]
+    fn parse_cgroup_prefers_unified_hierarchy() {
+        assert_eq!(
+            parse_cgroup("0::/user.slice/user-1000.slice/session-2.scope\n"),
+            Some("/user.slice/user-1000.slice/session-2.scope")
+        );
+        assert_eq!(
+            parse_cgroup("12:pids:/system.slice/ssh.service\n1:name=systemd:/init.scope\n0::/\n"),
+            Some("/")
+        );
+        assert_eq!(
+            parse_cgroup("12:pids:/system.slice/ssh.service\n1:name=systemd:/init.scope\n"),
+            Some("/system.slice/ssh.service")
+        );
+        assert_eq!(parse_cgroup(""), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_cache_reads_own_process() {
+        let mut cache = ProcessCache::new(Duration::from_secs(60));
+        let attributes = cache.attributes(std::process::id());
+        assert!(attributes
+            .iter()
+            .any(|kv| kv.key.as_str() == attribute::REQUEST_COMM));
+    }
+
+    #[test]
+    fn u64_attributes_keep_their_bits() {
+        assert_eq!(
+            attribute::ino(u64::MAX),
+            KeyValue::new(attribute::INO, -1i64)
+        );
+        assert_eq!(
+            attribute::fh(1 << 63),
+            KeyValue::new(attribute::FH, i64::MIN)
+        );
+        assert_eq!(
+            attribute::parent(i64::MAX as u64 + 2),
+            KeyValue::new(attribute::PARENT, i64::MIN + 1)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_lossy() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        assert_eq!(
+            attribute::name(name),
+            KeyValue::new(attribute::NAME, "caf\u{fffd}.txt")
+        );
+        assert_eq!(
+            attribute::new_name(name),
+            KeyValue::new(attribute::NEW_NAME, "caf\u{fffd}.txt")
+        );
+    }
+
+    #[test]
+    fn errno_attribute_uses_symbolic_name() {
+        assert_eq!(
+            attribute::errno(libc::EIO),
+            KeyValue::new(attribute::ERRNO, "EIO")
+        );
+        assert_eq!(
+            attribute::errno(libc::ENOENT),
+            KeyValue::new(attribute::ERRNO, "ENOENT")
+        );
+    }
+
+    #[test]
+    fn errno_attribute_falls_back_to_number() {
+        assert_eq!(attribute::errno_name(4242), None);
+        assert_eq!(
+            attribute::errno(4242),
+            KeyValue::new(attribute::ERRNO, "4242")
+        );
     }
 }